@@ -17,7 +17,7 @@ use structopt::StructOpt;
 use tokio::fs::File;
 use tokio::io::stdin;
 
-use crate::ui::ui;
+use crate::ui::{ui, ui_epub};
 
 /// yap yet another pager
 ///
@@ -28,17 +28,48 @@ struct Options {
     /// The file to read.
     /// If not provided and standard input is not a TTY, yap will read from standard input instead.
     file: Option<PathBuf>,
+
+    /// Page within the last N rows of the terminal instead of taking over the whole screen.
+    #[structopt(long, parse(try_from_str = parse_inline_height))]
+    inline: Option<u16>,
+}
+
+/// Parse and validate the `--inline` argument.
+///
+/// The viewport needs at least one row for content and one for the status bar, and
+/// [`DocumentView`][DocumentView] reserves a further row and column of its own, so a height of `2`
+/// would leave no row for content and any height below `2` would underflow the `usize` subtraction
+/// that computes its size.
+///
+/// [DocumentView]: ui/document/struct.DocumentView.html
+fn parse_inline_height(s: &str) -> Result<u16, String> {
+    let height: u16 = s.parse().map_err(|_| format!("invalid number `{}'", s))?;
+
+    if height < 3 {
+        Err(format!(
+            "`--inline` must be at least 3 rows, got {}",
+            height
+        ))
+    } else {
+        Ok(height)
+    }
 }
 
 #[tokio::main]
 async fn run(options: Options) -> anyhow::Result<()> {
     if let Some(path) = options.file {
-        let f = File::open(&path)
-            .await
-            .context(format!("Could not open `{}' for reading", path.display()))?;
-        ui(f).await?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(String::from);
+
+        if extension.as_deref().map_or(false, |ext| ext.eq_ignore_ascii_case("epub")) {
+            ui_epub(path, options.inline).await?;
+        } else {
+            let f = File::open(&path)
+                .await
+                .context(format!("Could not open `{}' for reading", path.display()))?;
+            ui(f, extension, options.inline).await?;
+        }
     } else if !stdin().is_tty() {
-        ui(stdin()).await?;
+        ui(stdin(), None, options.inline).await?;
     } else {
         return Err(anyhow!("yap: requires file or pipe"));
     }
@@ -6,10 +6,13 @@
 //  option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+mod ansi;
 mod document;
+mod epub;
 mod vec2;
 
 use std::io::{self, StdoutLock, Write};
+use std::path::PathBuf;
 
 use crossterm::event::{Event, EventStream, KeyCode};
 use crossterm::style::{self, Attribute};
@@ -21,13 +24,21 @@ use futures::stream::TryStreamExt;
 use tokio::io::{AsyncRead, BufReader};
 use tokio::prelude::*;
 
-use crate::ui::document::{DocumentView, FileDocument, HelpDocument};
+use crate::ui::document::{AnyDocument, Document, DocumentView, HelpDocument};
+use crate::ui::epub::EpubDocument;
 use crate::ui::vec2::Vec2;
 
 /// Run the yap UI.
 ///
-/// The `input` arugment is the stream (either stdin or a file) that will be displayed.
-pub async fn ui<R>(input: R) -> crossterm::Result<()>
+/// The `input` argument is the stream (either stdin or a file) that will be displayed. If
+/// `extension` names a recognized source file extension, the input is rendered with syntax
+/// highlighting instead of as plain text. If `inline` is given, yap pages within the last `inline`
+/// rows of the terminal instead of taking over the whole screen.
+pub async fn ui<R>(
+    input: R,
+    extension: Option<String>,
+    inline: Option<u16>,
+) -> crossterm::Result<()>
 where
     R: AsyncRead + Unpin,
 {
@@ -35,7 +46,9 @@ where
     let mut input = BufReader::new(input).lines();
     let mut events = EventStream::new().fuse();
 
-    let mut ui_state = UiState::new(stdout.lock(), terminal::size()?.into());
+    let size = viewport_size(inline)?;
+    let document = AnyDocument::new(size.y - 2, extension.as_deref());
+    let mut ui_state = UiState::new(stdout.lock(), size, document, inline);
 
     ui_state.initialize_terminal()?;
 
@@ -59,13 +72,98 @@ where
     Ok(())
 }
 
-/// The current yap UI state.
-struct UiState<'a> {
+/// Run the yap UI for an EPUB e-book.
+///
+/// Unlike [`ui()`][ui], this does not stream lines in from an input source: the entire book is
+/// parsed up front so that chapter navigation and internal links can be resolved immediately. As
+/// with [`ui()`][ui], if `inline` is given, yap pages within the last `inline` rows of the
+/// terminal instead of taking over the whole screen.
+///
+/// [ui]: fn.ui.html
+pub async fn ui_epub(path: PathBuf, inline: Option<u16>) -> anyhow::Result<()> {
+    let document = EpubDocument::open(&path)?;
+
+    let stdout = io::stdout();
+    let mut events = EventStream::new().fuse();
+
+    let size = viewport_size(inline)?;
+    let mut ui_state = UiState::new(stdout.lock(), size, document, inline);
+    ui_state.initialize_terminal()?;
+
+    loop {
+        let event = events.try_next().await?;
+        ui_state.handle_event(event)?;
+
+        if ui_state.should_exit() {
+            break;
+        }
+    }
+
+    ui_state.finalize_terminal()?;
+
+    Ok(())
+}
+
+/// The size of the viewport: the whole terminal, or the last `inline` rows of it if given.
+fn viewport_size(inline: Option<u16>) -> crossterm::Result<Vec2> {
+    let (width, height) = terminal::size()?;
+
+    Ok(match inline {
+        Some(inline_height) => Vec2 {
+            x: width as usize,
+            y: inline_height as usize,
+        },
+        None => Vec2 {
+            x: width as usize,
+            y: height as usize,
+        },
+    })
+}
+
+/// The current input mode of the UI.
+enum InputMode {
+    /// Keys are interpreted as commands.
+    Normal,
+
+    /// A search pattern is being read from the user, to be run on `Enter`.
+    Search { buffer: String },
+}
+
+/// The current yap UI state, generic over the kind of document being viewed (plain or
+/// syntax-highlighted text via [`AnyDocument`][AnyDocument], or an e-book via
+/// [`EpubDocument`][EpubDocument]).
+///
+/// All navigation, search, and count-prefix handling lives here; document-specific behavior (e.g.
+/// chapter navigation and internal links) is reached through the [`Document`][Document] trait, so
+/// it falls out of the generic methods below with no chapter- or link-specific code in this struct.
+///
+/// [AnyDocument]: document/enum.AnyDocument.html
+/// [EpubDocument]: epub/struct.EpubDocument.html
+/// [Document]: document/trait.Document.html
+struct UiState<'a, D: Document> {
+    /// The row of the terminal that the view's top-left corner is drawn at; always `0` unless
+    /// `inline_height` is set.
+    base_row: u16,
+
     /// The document being viewed.
-    document_view: DocumentView<FileDocument>,
+    document_view: DocumentView<D>,
 
     help_view: Option<DocumentView<HelpDocument>>,
 
+    /// If set, yap is confined to this many rows at the bottom of the terminal instead of taking
+    /// over the whole screen.
+    inline_height: Option<u16>,
+
+    /// The current input mode.
+    input_mode: InputMode,
+
+    /// A count prefix accumulated from digit key presses, to be consumed by the next command
+    /// that accepts one (currently just `g`).
+    count: Option<usize>,
+
+    /// Whether the last key press was a `g` awaiting a second `g` to complete the `gg` binding.
+    pending_g: bool,
+
     /// Whether or not yap should exit.
     should_exit: bool,
 
@@ -76,18 +174,28 @@ struct UiState<'a> {
     stdout: StdoutLock<'a>,
 }
 
-impl<'a> UiState<'a> {
+impl<'a, D: Document> UiState<'a, D> {
     /// Create a new UiState.
-    pub fn new(stdout: StdoutLock<'a>, size: Vec2) -> Self {
+    pub fn new(
+        stdout: StdoutLock<'a>,
+        size: Vec2,
+        document: D,
+        inline_height: Option<u16>,
+    ) -> Self {
         UiState {
+            base_row: 0,
             document_view: DocumentView::new(
-                FileDocument::new(size.y - 2),
+                document,
                 Vec2 {
                     x: size.x - 2,
                     y: size.y - 2,
                 },
             ),
+            count: None,
             help_view: None,
+            inline_height,
+            input_mode: InputMode::Normal,
+            pending_g: false,
             should_exit: false,
             size,
             stdout,
@@ -101,21 +209,49 @@ impl<'a> UiState<'a> {
 
     /// Initialize the terminal.
     ///
-    /// This method will enable raw mode on the tty, switch to the alternate screen, and hide the
-    /// cursor.
+    /// In the normal case, this enables raw mode, switches to the alternate screen, and hides the
+    /// cursor. In `--inline` mode, the alternate screen is never entered: instead, `inline_height`
+    /// rows are reserved by scrolling the existing buffer, and the cursor is moved back up to the
+    /// top of that freshly reserved region, which becomes `base_row`.
     pub fn initialize_terminal(&mut self) -> crossterm::Result<()> {
         enable_raw_mode()?;
-        execute!(self.stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        match self.inline_height {
+            Some(height) => {
+                for _ in 0..height {
+                    execute!(self.stdout, style::Print("\r\n"))?;
+                }
+                execute!(self.stdout, cursor::MoveUp(height), cursor::Hide)?;
+
+                let (_, row) = cursor::position()?;
+                self.base_row = row;
+                self.document_view.set_base_row(row);
+            }
+            None => {
+                execute!(self.stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+            }
+        }
+
         Ok(())
     }
 
     /// Finalize the terminal, returning its state to normal.
     ///
-    /// This method undoes the transforms from [`initialize_terminal()`][initialize_terminal].
+    /// This method undoes the transforms from [`initialize_terminal()`][initialize_terminal]. In
+    /// `--inline` mode, the viewport's contents are left printed in place and the scrollback above
+    /// them is untouched; the cursor is simply moved below the viewport.
     ///
     /// [initialize_terminal]: struct.UiState.html#method.initialize_terminal
     pub fn finalize_terminal(&mut self) -> crossterm::Result<()> {
-        execute!(self.stdout, terminal::LeaveAlternateScreen, cursor::Show)?;
+        match self.inline_height {
+            Some(height) => {
+                execute!(self.stdout, cursor::MoveTo(0, self.base_row + height), cursor::Show)?;
+            }
+            None => {
+                execute!(self.stdout, terminal::LeaveAlternateScreen, cursor::Show)?;
+            }
+        }
+
         disable_raw_mode()?;
         Ok(())
     }
@@ -136,33 +272,152 @@ impl<'a> UiState<'a> {
         match event {
             Event::Mouse(..) => unreachable!("yap does not have mouse support"),
             Event::Resize(x, y) => self.handle_resize((x, y).into())?,
-            Event::Key(key) => match key.code {
-                KeyCode::Char('q') | KeyCode::Char('Q') => self.quit()?,
-                KeyCode::Char('h') => self.pan_left()?,
-                KeyCode::Char('j') => self.scroll_down()?,
-                KeyCode::Char('k') => self.scroll_up()?,
-                KeyCode::Char('l') => self.pan_right()?,
-                KeyCode::Char(' ') | KeyCode::PageDown => self.next_page()?,
-                KeyCode::Char('?') => self.show_help()?,
-                KeyCode::PageUp => self.prev_page()?,
-                _ => {}
-            },
+            Event::Key(key) => {
+                if matches!(self.input_mode, InputMode::Search { .. }) {
+                    self.handle_search_key(key.code)?;
+                } else {
+                    match key.code {
+                        KeyCode::Char(c) if c.is_ascii_digit() => {
+                            let digit = c.to_digit(10).unwrap() as usize;
+                            // Saturate rather than overflow: held or auto-repeated digit keys can
+                            // easily produce more digits in a row than any real line count needs,
+                            // and a plain multiply-and-add would panic in a debug build once it
+                            // overflowed `usize`.
+                            self.count = Some(
+                                self.count
+                                    .unwrap_or(0)
+                                    .saturating_mul(10)
+                                    .saturating_add(digit),
+                            );
+                        }
+                        KeyCode::Char('g') => self.handle_g()?,
+                        KeyCode::Char('G') => self.goto_end()?,
+                        _ => {
+                            self.count = None;
+                            self.pending_g = false;
+
+                            match key.code {
+                                KeyCode::Char('q') | KeyCode::Char('Q') => self.quit()?,
+                                KeyCode::Char('h') => self.pan_left()?,
+                                KeyCode::Char('j') => self.scroll_down()?,
+                                KeyCode::Char('k') => self.scroll_up()?,
+                                KeyCode::Char('l') => self.pan_right()?,
+                                KeyCode::Char(' ') | KeyCode::PageDown => self.next_page()?,
+                                KeyCode::Char('?') => self.show_help()?,
+                                KeyCode::PageUp => self.prev_page()?,
+                                KeyCode::Char('/') => self.start_search()?,
+                                KeyCode::Char('n') => self.next_match()?,
+                                KeyCode::Char('N') => self.prev_match()?,
+                                KeyCode::Char(']') => self.next_chapter()?,
+                                KeyCode::Char('[') => self.prev_chapter()?,
+                                KeyCode::Enter => self.follow_link()?,
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         Ok(())
     }
 
-    /// Handle a line being buffered in from the input stream.
+    /// Begin reading a search pattern from the user.
+    fn start_search(&mut self) -> crossterm::Result<()> {
+        self.input_mode = InputMode::Search {
+            buffer: String::new(),
+        };
+        self.draw_prompt()
+    }
+
+    /// Handle a key press while a search pattern is being read.
+    fn handle_search_key(&mut self, code: KeyCode) -> crossterm::Result<()> {
+        match code {
+            KeyCode::Esc => {
+                self.input_mode = InputMode::Normal;
+                self.draw_status_bar()?;
+                self.stdout.flush()?;
+            }
+            KeyCode::Enter => {
+                let pattern = match &self.input_mode {
+                    InputMode::Search { buffer } => buffer.clone(),
+                    InputMode::Normal => unreachable!(),
+                };
+                self.input_mode = InputMode::Normal;
+                match self.document_view.search(&mut self.stdout, &pattern) {
+                    Ok(_) => self.draw_status_bar()?,
+                    Err(e) => self.draw_error(&format!("Invalid pattern: {}", e))?,
+                }
+                self.stdout.flush()?;
+            }
+            KeyCode::Backspace => {
+                if let InputMode::Search { buffer } = &mut self.input_mode {
+                    buffer.pop();
+                }
+                self.draw_prompt()?;
+            }
+            KeyCode::Char(c) => {
+                if let InputMode::Search { buffer } = &mut self.input_mode {
+                    buffer.push(c);
+                }
+                self.draw_prompt()?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Draw the search prompt in place of the status bar.
+    fn draw_prompt(&mut self) -> crossterm::Result<()> {
+        let buffer = match &self.input_mode {
+            InputMode::Search { buffer } => buffer,
+            InputMode::Normal => return Ok(()),
+        };
+
+        execute!(
+            self.stdout,
+            cursor::MoveTo(0, self.status_row()),
+            terminal::Clear(ClearType::CurrentLine),
+            style::Print(format!("/{}", buffer)),
+        )
+    }
+
+    /// Draw an error message in place of the status bar, in response to a bad search pattern.
+    fn draw_error(&mut self, message: &str) -> crossterm::Result<()> {
+        execute!(
+            self.stdout,
+            cursor::MoveTo(0, self.status_row()),
+            terminal::Clear(ClearType::CurrentLine),
+            style::SetAttribute(Attribute::Reverse),
+            style::Print(message),
+            style::SetAttribute(Attribute::NoReverse),
+        )
+    }
+
+    /// The row the status bar (or search prompt) is drawn on.
+    fn status_row(&self) -> u16 {
+        self.base_row + (self.size.y - 1) as u16
+    }
+
+    /// Clear the view, in preparation for a full redraw.
     ///
-    /// The line will be displayed if there is room to draw it.
-    pub fn handle_line(&mut self, line: String) -> crossterm::Result<()> {
-        let index = self.document_view.document().push_line(line);
-        if self.help_view.is_none()
-            && self
-                .document_view
-                .queue_line_if_visible(&mut self.stdout, index)?
-        {
-            self.stdout.flush()?;
+    /// In `--inline` mode, this only clears the rows belonging to the view; clearing the whole
+    /// screen would destroy scrollback content above the view that yap does not own.
+    fn clear(&mut self) -> crossterm::Result<()> {
+        match self.inline_height {
+            Some(height) => {
+                for row in self.base_row..self.base_row + height {
+                    execute!(
+                        self.stdout,
+                        cursor::MoveTo(0, row),
+                        terminal::Clear(ClearType::CurrentLine)
+                    )?;
+                }
+            }
+            None => {
+                execute!(self.stdout, terminal::Clear(ClearType::All))?;
+            }
         }
 
         Ok(())
@@ -172,8 +427,17 @@ impl<'a> UiState<'a> {
     ///
     /// The entire screen will be cleared and re-drawn.
     fn handle_resize(&mut self, new_size: Vec2) -> crossterm::Result<()> {
+        // In `--inline` mode the view's height is fixed; only the width tracks the terminal.
+        let new_size = match self.inline_height {
+            Some(height) => Vec2 {
+                x: new_size.x,
+                y: height as usize,
+            },
+            None => new_size,
+        };
+
         self.size = new_size;
-        execute!(self.stdout, terminal::Clear(ClearType::All))?;
+        self.clear()?;
         self.draw_status_bar()?;
         self.document_view.resize(Vec2 {
             x: new_size.x - 2,
@@ -195,26 +459,34 @@ impl<'a> UiState<'a> {
     ///
     /// Note: this method does not reposition the cursor after moving it to the status line.
     fn draw_status_bar(&mut self) -> crossterm::Result<()> {
+        let mut status = String::from("[yap] q to exit, ? for help");
+        if let Some(suffix) = self.document_view.document().status_suffix() {
+            status.push(' ');
+            status.push_str(&suffix);
+        }
+
         execute!(
             self.stdout,
-            cursor::MoveTo(0, (self.size.y - 1) as u16),
+            cursor::MoveTo(0, self.status_row()),
             style::SetAttribute(Attribute::Reverse),
-            style::Print("[yap] q to exit, ? for help"),
+            style::Print(status),
             style::SetAttribute(Attribute::NoReverse),
         )
     }
 
     fn show_help(&mut self) -> crossterm::Result<()> {
         if self.help_view.is_none() {
-            let help_view = DocumentView::new(
+            let mut help_view = DocumentView::new(
                 HelpDocument,
                 Vec2 {
                     x: self.size.x - 2,
                     y: self.size.y - 2,
                 },
             );
+            help_view.set_base_row(self.base_row);
+            help_view.set_show_gutter(false);
 
-            execute!(self.stdout, terminal::Clear(ClearType::All))?;
+            self.clear()?;
             self.draw_status_bar()?;
             help_view.redraw(&mut self.stdout)?;
 
@@ -227,7 +499,7 @@ impl<'a> UiState<'a> {
     fn quit(&mut self) -> crossterm::Result<()> {
         if self.help_view.is_some() {
             self.help_view = None;
-            execute!(self.stdout, terminal::Clear(ClearType::All))?;
+            self.clear()?;
             self.draw_status_bar()?;
             self.document_view.redraw(&mut self.stdout)
         } else {
@@ -235,6 +507,99 @@ impl<'a> UiState<'a> {
             Ok(())
         }
     }
+
+    /// Move to the next chapter, resetting the view to its top-left. A no-op for documents with
+    /// no chapter structure.
+    fn next_chapter(&mut self) -> crossterm::Result<()> {
+        if self.document_view.document().next_chapter() {
+            self.jump_to_top()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Move to the previous chapter. See [`next_chapter()`][UiState::next_chapter].
+    fn prev_chapter(&mut self) -> crossterm::Result<()> {
+        if self.document_view.document().prev_chapter() {
+            self.jump_to_top()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Resolve the nearest link at or after the current top line and jump to the chapter and line
+    /// it targets, if any. A no-op for documents with no internal links.
+    fn follow_link(&mut self) -> crossterm::Result<()> {
+        let top_line = self.document_view.offset().y;
+        let target = self.document_view.document().follow_link(top_line);
+
+        if let Some((chapter, line)) = target {
+            self.document_view.document().goto_chapter(chapter);
+            self.document_view.reset_offset();
+            self.document_view.set_line_offset(line);
+
+            self.clear()?;
+            self.draw_status_bar()?;
+            self.document_view.redraw(&mut self.stdout)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reset the view to the top-left of the current chapter and redraw.
+    fn jump_to_top(&mut self) -> crossterm::Result<()> {
+        self.document_view.reset_offset();
+        self.clear()?;
+        self.draw_status_bar()?;
+        self.document_view.redraw(&mut self.stdout)
+    }
+
+    /// Handle a `g` key press: jump to a pending count's line, complete a `gg` (jump to the top),
+    /// or start waiting for a second `g`.
+    fn handle_g(&mut self) -> crossterm::Result<()> {
+        if let Some(count) = self.count.take() {
+            self.pending_g = false;
+            self.goto_line(count)
+        } else if self.pending_g {
+            self.pending_g = false;
+            self.goto_line(1)
+        } else {
+            self.pending_g = true;
+            Ok(())
+        }
+    }
+
+    /// Jump to the end of the document.
+    fn goto_end(&mut self) -> crossterm::Result<()> {
+        self.count = None;
+        self.pending_g = false;
+        self.set_line_offset(usize::MAX);
+        self.redraw_current()
+    }
+
+    /// Jump so that the given 1-based line number is visible.
+    fn goto_line(&mut self, line: usize) -> crossterm::Result<()> {
+        self.set_line_offset(line.saturating_sub(1));
+        self.redraw_current()
+    }
+
+    /// Set the line offset on whichever view (help or document) is currently active.
+    fn set_line_offset(&mut self, line: usize) {
+        if let Some(help_view) = self.help_view.as_mut() {
+            help_view.set_line_offset(line);
+        } else {
+            self.document_view.set_line_offset(line);
+        }
+    }
+
+    /// Redraw whichever view (help or document) is currently active.
+    fn redraw_current(&mut self) -> crossterm::Result<()> {
+        if let Some(help_view) = self.help_view.as_mut() {
+            help_view.redraw(&mut self.stdout)
+        } else {
+            self.document_view.redraw(&mut self.stdout)
+        }
+    }
 }
 
 macro_rules! impl_document_view_methods {
@@ -258,7 +623,7 @@ macro_rules! impl_document_view_methods {
     () => {};
 }
 
-impl<'a> UiState<'a> {
+impl<'a, D: Document> UiState<'a, D> {
     impl_document_view_methods! {
         /// Pan left by one column if we are not at the first column of the document.
         pan_left,
@@ -279,5 +644,29 @@ impl<'a> UiState<'a> {
         /// Scroll the document down by up to half the height of the terminal if there is more
         /// document to view.
         next_page,
+
+        /// Advance to the next search match, wrapping around to the first match if necessary.
+        next_match,
+
+        /// Retreat to the previous search match, wrapping around to the last match if necessary.
+        prev_match,
+    }
+}
+
+impl<'a> UiState<'a, AnyDocument> {
+    /// Handle a line being buffered in from the input stream.
+    ///
+    /// The line will be displayed if there is room to draw it.
+    pub fn handle_line(&mut self, line: String) -> crossterm::Result<()> {
+        let index = self.document_view.document().push_line(line);
+        if self.help_view.is_none()
+            && self
+                .document_view
+                .queue_line_if_visible(&mut self.stdout, index)?
+        {
+            self.stdout.flush()?;
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,180 @@
+// Copyright 2020 Barret Rennie
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+//  option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A small state machine for interpreting CSI SGR ("Select Graphic Rendition") escape sequences,
+//! so that colored input (e.g. `grep --color`) renders the way `less -R` would instead of having
+//! its escape codes printed verbatim.
+
+use crossterm::style::{Attribute, Color, ContentStyle};
+
+/// A run of text that shares a single style.
+#[derive(Clone)]
+pub struct StyledRun {
+    /// The text of this run, with all escape sequences removed.
+    pub text: String,
+
+    /// The style in effect for this run.
+    pub style: ContentStyle,
+}
+
+/// Parse `line`, interpreting CSI SGR sequences (`ESC [ ... m`) and dropping every other escape
+/// sequence (CSI, OSC/DCS/SOS/PM/APC strings, and single-character escapes alike), returning the
+/// line split into styled runs.
+///
+/// `style` is the style in effect at the start of the line, carried over from the previous line
+/// (an SGR sequence with no explicit reset stays in effect across line breaks, as in `less -R`),
+/// and is updated in place to reflect the style in effect at the end of `line`.
+pub fn parse_line(line: &str, style: &mut ContentStyle) -> Vec<StyledRun> {
+    let mut runs = Vec::new();
+    let mut current_text = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            current_text.push(c);
+            continue;
+        }
+
+        match chars.peek() {
+            Some('[') => {
+                chars.next(); // consume '['
+
+                // CSI: parameter bytes (0x30-0x3F), then intermediate bytes (0x20-0x2F), then one
+                // final byte (0x40-0x7E).
+                let mut params = String::new();
+                while let Some(&c) = chars.peek() {
+                    if ('\u{30}'..='\u{3f}').contains(&c) {
+                        params.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                while let Some(&c) = chars.peek() {
+                    if ('\u{20}'..='\u{2f}').contains(&c) {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let final_byte = chars.next();
+
+                // Only SGR sequences (ending in `m`) affect rendering; anything else (cursor
+                // movement, screen clears, ...) has no meaning in a pager and is dropped.
+                if final_byte != Some('m') {
+                    continue;
+                }
+
+                if !current_text.is_empty() {
+                    runs.push(StyledRun {
+                        text: std::mem::take(&mut current_text),
+                        style: *style,
+                    });
+                }
+
+                apply_sgr(&params, style);
+            }
+            Some(']') | Some('P') | Some('X') | Some('^') | Some('_') => {
+                // OSC/DCS/SOS/PM/APC: a "string" sequence terminated by BEL or ST (`ESC \`), used
+                // for things like OSC 8 hyperlinks or setting the window title. It has no meaning
+                // in a pager, so it is dropped in its entirety.
+                chars.next(); // consume the introducer
+                loop {
+                    match chars.next() {
+                        None | Some('\u{7}') => break,
+                        Some('\u{1b}') => {
+                            if chars.peek() == Some(&'\\') {
+                                chars.next();
+                            }
+                            break;
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+            Some(_) => {
+                // A single-character (Fp/Fe/Fs) escape, with any number of intermediate bytes
+                // (0x20-0x2F) before the final byte: consume and drop it.
+                while let Some(&c) = chars.peek() {
+                    if ('\u{20}'..='\u{2f}').contains(&c) {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                chars.next();
+            }
+            None => {}
+        }
+    }
+
+    if !current_text.is_empty() {
+        runs.push(StyledRun {
+            text: current_text,
+            style: *style,
+        });
+    }
+
+    runs
+}
+
+/// Apply the effect of an SGR parameter string (e.g. `"1;31"`) to `style`.
+fn apply_sgr(params: &str, style: &mut ContentStyle) {
+    let codes: Vec<u16> = if params.is_empty() {
+        vec![0]
+    } else {
+        params.split(';').map(|p| p.parse().unwrap_or(0)).collect()
+    };
+
+    for code in codes {
+        match code {
+            0 => *style = ContentStyle::default(),
+            1 => style.attributes.set(Attribute::Bold),
+            4 => style.attributes.set(Attribute::Underlined),
+            7 => style.attributes.set(Attribute::Reverse),
+            22 => style.attributes.unset(Attribute::Bold),
+            24 => style.attributes.unset(Attribute::Underlined),
+            27 => style.attributes.unset(Attribute::Reverse),
+            30..=37 => style.foreground_color = Some(ansi_color(code - 30)),
+            39 => style.foreground_color = None,
+            40..=47 => style.background_color = Some(ansi_color(code - 40)),
+            49 => style.background_color = None,
+            90..=97 => style.foreground_color = Some(ansi_bright_color(code - 90)),
+            100..=107 => style.background_color = Some(ansi_bright_color(code - 100)),
+            _ => {}
+        }
+    }
+}
+
+fn ansi_color(code: u16) -> Color {
+    match code {
+        0 => Color::Black,
+        1 => Color::DarkRed,
+        2 => Color::DarkGreen,
+        3 => Color::DarkYellow,
+        4 => Color::DarkBlue,
+        5 => Color::DarkMagenta,
+        6 => Color::DarkCyan,
+        7 => Color::Grey,
+        _ => Color::Reset,
+    }
+}
+
+fn ansi_bright_color(code: u16) -> Color {
+    match code {
+        0 => Color::DarkGrey,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        7 => Color::White,
+        _ => Color::Reset,
+    }
+}
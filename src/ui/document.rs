@@ -7,12 +7,18 @@
 // except according to those terms.
 
 use std::cmp::{max, min};
-use std::io::{StdoutLock, Write};
+use std::io::{self, StdoutLock, Write};
 use std::ops::{Index, Range};
 
+use crossterm::style::{Attribute, Color, ContentStyle};
 use crossterm::terminal::{self, ClearType};
 use crossterm::{cursor, queue, style};
+use regex::Regex;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Style as SyntectStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
 
+use crate::ui::ansi::{self, StyledRun};
 use crate::ui::Vec2;
 
 /// A document that can be viewed.
@@ -22,16 +28,83 @@ pub trait Document: Index<usize, Output = str> {
 
     /// Return the number of lines in the document.
     fn len(&self) -> usize;
+
+    /// Return the styled runs that make up the given line.
+    ///
+    /// The default implementation returns the whole line as a single, unstyled run, which is
+    /// correct for any document whose lines are plain text.
+    fn styled_line(&self, index: usize) -> Vec<StyledRun> {
+        vec![StyledRun {
+            text: self[index].to_string(),
+            style: ContentStyle::default(),
+        }]
+    }
+
+    /// Move to the next chapter, for documents divided into chapters (e.g. an EPUB). Returns
+    /// `true` if the view moved. The default implementation is for documents with no chapter
+    /// structure, and never moves.
+    fn next_chapter(&mut self) -> bool {
+        false
+    }
+
+    /// Move to the previous chapter. See [`next_chapter()`][Document::next_chapter].
+    fn prev_chapter(&mut self) -> bool {
+        false
+    }
+
+    /// Jump directly to the given chapter index, for documents divided into chapters. A no-op by
+    /// default.
+    fn goto_chapter(&mut self, _chapter: usize) {}
+
+    /// Resolve the nearest link at or after `line` to the `(chapter, line)` it targets, for
+    /// documents that support internal links (e.g. an EPUB's table of contents). `None` by
+    /// default.
+    fn follow_link(&self, _line: usize) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Extra text appended to the status bar (e.g. `-- chapter 3/12`), or `None` if this document
+    /// type has nothing to add.
+    fn status_suffix(&self) -> Option<String> {
+        None
+    }
+}
+
+/// An active search query against a [`DocumentView`][DocumentView].
+///
+/// [DocumentView]: struct.DocumentView.html
+struct SearchState {
+    /// The compiled pattern being searched for.
+    regex: Regex,
+
+    /// Every match in the document, in document order, as `(line_index, byte_start, byte_end)`.
+    matches: Vec<(usize, usize, usize)>,
+
+    /// The index into `matches` that is currently focused.
+    current: usize,
 }
 
 /// A view into a document.
 pub struct DocumentView<D> {
+    /// The row of the terminal that this view's top-left corner is drawn at.
+    ///
+    /// This is `0` for a view that owns the whole screen (the normal, alternate-screen mode); for a
+    /// view confined to a reserved region of the terminal (`--inline` mode), it is the first row
+    /// of that region.
+    base_row: u16,
+
     /// The document being viewed.
     document: D,
 
     /// The offset into `lines.`
     offset: Vec2,
 
+    /// The current search query, if one has been run.
+    query: Option<SearchState>,
+
+    /// Whether to draw a left-hand gutter of line numbers.
+    show_gutter: bool,
+
     /// The size of the display region.
     size: Vec2,
 }
@@ -42,8 +115,11 @@ where
 {
     pub fn new(document: D, size: Vec2) -> Self {
         DocumentView {
+            base_row: 0,
             document,
             offset: Vec2::default(),
+            query: None,
+            show_gutter: true,
             size: size,
         }
     }
@@ -56,6 +132,53 @@ where
         self.size = new_size;
     }
 
+    /// Set the row of the terminal that this view's top-left corner is drawn at.
+    pub fn set_base_row(&mut self, base_row: u16) {
+        self.base_row = base_row;
+    }
+
+    /// Set whether a left-hand gutter of line numbers is drawn.
+    pub fn set_show_gutter(&mut self, show_gutter: bool) {
+        self.show_gutter = show_gutter;
+    }
+
+    /// The width of the line-number gutter, including its trailing space separator, or `0` if the
+    /// gutter is disabled.
+    fn gutter_width(&self) -> usize {
+        if !self.show_gutter {
+            return 0;
+        }
+
+        let mut digits = 1;
+        let mut len = self.document.len();
+        while len >= 10 {
+            len /= 10;
+            digits += 1;
+        }
+
+        digits + 1
+    }
+
+    /// The width available to draw text in, after reserving room for the gutter.
+    fn text_width(&self) -> usize {
+        self.size.x.saturating_sub(self.gutter_width())
+    }
+
+    /// The current offset into the document.
+    pub fn offset(&self) -> Vec2 {
+        self.offset
+    }
+
+    /// Reset the view back to the top-left of the document, without drawing.
+    pub fn reset_offset(&mut self) {
+        self.offset = Vec2::default();
+    }
+
+    /// Move the view directly to show `line`, without drawing.
+    pub fn set_line_offset(&mut self, line: usize) {
+        self.offset.y = min(line, self.document.len().saturating_sub(self.size.y));
+    }
+
     /// Pan left by one column if we are not at the first column of the document.
     pub fn pan_left<'a>(&mut self, stdout: &mut StdoutLock<'a>) -> crossterm::Result<()> {
         if self.offset.x > 0 {
@@ -88,7 +211,7 @@ where
 
     /// Pan right by one column if there is at least one more column of text off-screen.
     pub fn pan_right<'a>(&mut self, stdout: &mut StdoutLock<'a>) -> crossterm::Result<()> {
-        if self.document.max_line_len() > self.offset.x + self.size.x {
+        if self.document.max_line_len() > self.offset.x + self.text_width() {
             self.offset.x += 1;
             self.redraw(stdout)?;
         }
@@ -131,6 +254,17 @@ where
         stdout: &mut StdoutLock<'a>,
         index: usize,
     ) -> crossterm::Result<()> {
+        if self.show_gutter {
+            // The gutter's width already includes its trailing space separator.
+            let width = self.gutter_width() - 1;
+            queue!(
+                stdout,
+                style::SetAttribute(Attribute::Dim),
+                style::Print(format!("{:>width$} ", index + 1, width = width)),
+                style::SetAttribute(Attribute::Reset),
+            )?;
+        }
+
         let line = &self.document[index];
         let mut char_indices = line.char_indices().map(|(idx, _)| idx);
 
@@ -149,16 +283,136 @@ where
 
         // If the line would be too long to display from `start`, find the index of the character
         // one past the screen. Otherwise, we can default to the string length.
-        let end = char_indices.nth(self.size.x).unwrap_or(line.len());
+        let end = char_indices.nth(self.text_width()).unwrap_or(line.len());
+
+        // Any matches on this line, as `(byte_start, byte_end)`, to be shown in reverse video.
+        let matches: Vec<(usize, usize)> = self
+            .query
+            .as_ref()
+            .map(|query| {
+                query
+                    .matches
+                    .iter()
+                    .filter(|&&(line, _, _)| line == index)
+                    .map(|&(_, start, end)| (start, end))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Walk the line's styled runs, clipping each to the visible [start, end) byte range and
+        // further splitting it around any search matches it overlaps.
+        let mut run_start = 0;
+        for run in self.document.styled_line(index) {
+            let this_run_start = run_start;
+            run_start += run.text.len();
+
+            let clip_start = max(this_run_start, start);
+            let clip_end = min(run_start, end);
+            if clip_start >= clip_end {
+                continue;
+            }
+
+            let mut pos = clip_start;
+            for &(match_start, match_end) in &matches {
+                let seg_start = max(match_start, pos);
+                let seg_end = min(match_end, clip_end);
+                if seg_start >= seg_end {
+                    continue;
+                }
+
+                queue_run(stdout, &run, this_run_start, pos, seg_start, false)?;
+                queue_run(stdout, &run, this_run_start, seg_start, seg_end, true)?;
+                pos = seg_end;
+            }
+            queue_run(stdout, &run, this_run_start, pos, clip_end, false)?;
+        }
 
         queue!(
             stdout,
-            style::Print(&line[start..end]),
+            style::SetAttribute(Attribute::Reset),
             terminal::Clear(ClearType::UntilNewLine),
             cursor::MoveToNextLine(1),
         )
     }
 
+    /// Compile `pattern` and search the document for matches.
+    ///
+    /// All matches in the document are recorded so that [`next_match()`][next_match] and
+    /// [`prev_match()`][prev_match] can cycle through them. The view is scrolled so that the first
+    /// match at or after the current offset (wrapping around to the start of the document if
+    /// necessary) is visible. If no matches are found, any previous query is cleared and `false` is
+    /// returned.
+    ///
+    /// [next_match]: struct.DocumentView.html#method.next_match
+    /// [prev_match]: struct.DocumentView.html#method.prev_match
+    pub fn search<'a>(
+        &mut self,
+        stdout: &mut StdoutLock<'a>,
+        pattern: &str,
+    ) -> crossterm::Result<bool> {
+        let regex = Regex::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut matches = Vec::new();
+        for line_index in 0..self.document.len() {
+            let line = &self.document[line_index];
+            for m in regex.find_iter(line) {
+                matches.push((line_index, m.start(), m.end()));
+            }
+        }
+
+        if matches.is_empty() {
+            self.query = None;
+            return Ok(false);
+        }
+
+        let current = matches
+            .iter()
+            .position(|&(line, _, _)| line >= self.offset.y)
+            .unwrap_or(0);
+
+        self.offset.y = min(
+            matches[current].0,
+            self.document.len().saturating_sub(self.size.y),
+        );
+        self.query = Some(SearchState {
+            regex,
+            matches,
+            current,
+        });
+        self.redraw(stdout)?;
+
+        Ok(true)
+    }
+
+    /// Advance to the next search match, wrapping around to the first match if necessary.
+    pub fn next_match<'a>(&mut self, stdout: &mut StdoutLock<'a>) -> crossterm::Result<()> {
+        self.advance_match(stdout, 1)
+    }
+
+    /// Retreat to the previous search match, wrapping around to the last match if necessary.
+    pub fn prev_match<'a>(&mut self, stdout: &mut StdoutLock<'a>) -> crossterm::Result<()> {
+        self.advance_match(stdout, -1)
+    }
+
+    fn advance_match<'a>(
+        &mut self,
+        stdout: &mut StdoutLock<'a>,
+        direction: isize,
+    ) -> crossterm::Result<()> {
+        let query = match self.query.as_mut() {
+            Some(query) if !query.matches.is_empty() => query,
+            _ => return Ok(()),
+        };
+
+        let len = query.matches.len() as isize;
+        let next = (query.current as isize + direction).rem_euclid(len) as usize;
+        query.current = next;
+
+        let line = query.matches[next].0;
+        self.offset.y = min(line, self.document.len().saturating_sub(self.size.y));
+        self.redraw(stdout)
+    }
+
     pub fn queue_line_if_visible<'a>(
         &self,
         stdout: &mut StdoutLock<'a>,
@@ -174,7 +428,7 @@ where
 
     /// Redraw the document to the screen.
     pub fn redraw<'a>(&self, stdout: &mut StdoutLock<'a>) -> crossterm::Result<()> {
-        queue!(stdout, cursor::MoveTo(0, 0))?;
+        queue!(stdout, cursor::MoveTo(0, self.base_row))?;
 
         for y in self.visible_lines() {
             self.queue_line(stdout, y)?;
@@ -196,13 +450,64 @@ where
     }
 }
 
+/// Print the `[from, to)` byte range of `run`'s text (byte offsets relative to the start of the
+/// line, `run` having started at `run_start`), applying `run`'s style and, if `reverse` is set,
+/// reversing the video on top of it.
+fn queue_run<'a>(
+    stdout: &mut StdoutLock<'a>,
+    run: &StyledRun,
+    run_start: usize,
+    from: usize,
+    to: usize,
+    reverse: bool,
+) -> crossterm::Result<()> {
+    if from >= to {
+        return Ok(());
+    }
+
+    queue!(stdout, style::SetAttribute(Attribute::Reset))?;
+    if let Some(fg) = run.style.foreground_color {
+        queue!(stdout, style::SetForegroundColor(fg))?;
+    }
+    if let Some(bg) = run.style.background_color {
+        queue!(stdout, style::SetBackgroundColor(bg))?;
+    }
+    if run.style.attributes.has(Attribute::Bold) {
+        queue!(stdout, style::SetAttribute(Attribute::Bold))?;
+    }
+    if run.style.attributes.has(Attribute::Italic) {
+        queue!(stdout, style::SetAttribute(Attribute::Italic))?;
+    }
+    if run.style.attributes.has(Attribute::Underlined) {
+        queue!(stdout, style::SetAttribute(Attribute::Underlined))?;
+    }
+    if reverse || run.style.attributes.has(Attribute::Reverse) {
+        queue!(stdout, style::SetAttribute(Attribute::Reverse))?;
+    }
+
+    queue!(
+        stdout,
+        style::Print(&run.text[from - run_start..to - run_start])
+    )
+}
+
 /// A document representing the file being read.
 pub struct FileDocument {
-    /// The lines of the document.
+    /// The lines of the document, as plain text with all escape sequences stripped.
+    ///
+    /// This is kept alongside `styled_lines` so that line length calculations and searches can
+    /// operate on visible text without having to account for escape sequences.
     lines: Vec<String>,
 
-    /// The maximum line length.
+    /// The styled runs that make up each line, used when rendering.
+    styled_lines: Vec<Vec<StyledRun>>,
+
+    /// The maximum line length, in visible characters.
     max_line_len: usize,
+
+    /// The SGR style carried over from the end of the last pushed line, since a color or
+    /// attribute set on one line stays in effect on the next until it is reset.
+    style: ContentStyle,
 }
 
 impl FileDocument {
@@ -210,17 +515,25 @@ impl FileDocument {
     pub fn new(height: usize) -> Self {
         FileDocument {
             lines: Vec::with_capacity(height),
+            styled_lines: Vec::with_capacity(height),
             max_line_len: 0,
+            style: ContentStyle::default(),
         }
     }
 
-    /// Push the given line into the document.
+    /// Push the given line into the document, interpreting any SGR escape sequences it contains.
     ///
     /// The index of the inserted line is returned.
     pub fn push_line(&mut self, line: String) -> usize {
         let index = self.lines.len();
-        self.max_line_len = max(self.max_line_len, line.chars().count());
-        self.lines.push(line);
+
+        let runs = ansi::parse_line(&line, &mut self.style);
+        let plain: String = runs.iter().map(|run| run.text.as_str()).collect();
+
+        self.max_line_len = max(self.max_line_len, plain.chars().count());
+        self.lines.push(plain);
+        self.styled_lines.push(runs);
+
         index
     }
 }
@@ -240,4 +553,189 @@ impl Document for FileDocument {
     fn len(&self) -> usize {
         self.lines.len()
     }
+    fn styled_line(&self, index: usize) -> Vec<StyledRun> {
+        self.styled_lines[index].clone()
+    }
+}
+
+/// A document representing a source file, rendered with syntax highlighting.
+pub struct HighlightDocument {
+    /// The lines of the document.
+    lines: Vec<String>,
+
+    /// The highlighted runs that make up each line.
+    styled_lines: Vec<Vec<StyledRun>>,
+
+    /// The maximum line length.
+    max_line_len: usize,
+
+    /// The syntax definitions available to `highlighter`.
+    syntax_set: &'static SyntaxSet,
+
+    /// The incremental highlighter. Its parse state advances by one line with every call to
+    /// [`push_line()`][push_line], so only the newly pushed line is ever (re-)highlighted.
+    ///
+    /// [push_line]: struct.HighlightDocument.html#method.push_line
+    highlighter: HighlightLines<'static>,
+}
+
+impl HighlightDocument {
+    /// Create a new `HighlightDocument` for a file with the given extension.
+    ///
+    /// Returns `None` if no syntax is registered for `extension`.
+    pub fn new(height: usize, extension: &str) -> Option<Self> {
+        // The default syntax and theme sets are leaked to obtain `'static` references: the
+        // highlighter needs to borrow a `Theme` for the lifetime of the document, and there is
+        // exactly one of these per `yap` invocation, so the leak is bounded.
+        let syntax_set: &'static SyntaxSet = Box::leak(Box::new(SyntaxSet::load_defaults_newlines()));
+        let theme_set: &'static ThemeSet = Box::leak(Box::new(ThemeSet::load_defaults()));
+
+        let syntax = syntax_set.find_syntax_by_extension(extension)?;
+        let theme = &theme_set.themes["base16-ocean.dark"];
+
+        Some(HighlightDocument {
+            lines: Vec::with_capacity(height),
+            styled_lines: Vec::with_capacity(height),
+            max_line_len: 0,
+            syntax_set,
+            highlighter: HighlightLines::new(syntax, theme),
+        })
+    }
+
+    /// Push the given line into the document, highlighting it against the current parser state.
+    ///
+    /// The index of the inserted line is returned.
+    pub fn push_line(&mut self, line: String) -> usize {
+        let index = self.lines.len();
+        self.max_line_len = max(self.max_line_len, line.chars().count());
+
+        // syntect expects the trailing newline to be present for correct tokenization.
+        let mut with_newline = line.clone();
+        with_newline.push('\n');
+
+        let runs = self
+            .highlighter
+            .highlight_line(&with_newline, self.syntax_set)
+            .map(|ranges| {
+                ranges
+                    .into_iter()
+                    .map(|(style, text)| StyledRun {
+                        text: text.trim_end_matches('\n').to_string(),
+                        style: to_content_style(style),
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(|_| {
+                // If highlighting fails, fall back to the plain, unstyled line rather than
+                // silently dropping its content.
+                vec![StyledRun {
+                    text: line.clone(),
+                    style: ContentStyle::default(),
+                }]
+            });
+
+        self.lines.push(line);
+        self.styled_lines.push(runs);
+
+        index
+    }
+}
+
+/// Convert a syntect style into the `ContentStyle` used to render it.
+fn to_content_style(style: SyntectStyle) -> ContentStyle {
+    let mut content_style = ContentStyle::default();
+    content_style.foreground_color = Some(Color::Rgb {
+        r: style.foreground.r,
+        g: style.foreground.g,
+        b: style.foreground.b,
+    });
+
+    if style.font_style.contains(FontStyle::BOLD) {
+        content_style.attributes.set(Attribute::Bold);
+    }
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        content_style.attributes.set(Attribute::Underlined);
+    }
+
+    content_style
+}
+
+impl Index<usize> for HighlightDocument {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        &self.lines[index]
+    }
+}
+
+impl Document for HighlightDocument {
+    fn max_line_len(&self) -> usize {
+        self.max_line_len
+    }
+    fn len(&self) -> usize {
+        self.lines.len()
+    }
+    fn styled_line(&self, index: usize) -> Vec<StyledRun> {
+        self.styled_lines[index].clone()
+    }
+}
+
+/// The document being viewed in the main pane: either syntax-highlighted source, when a known
+/// syntax was found for the input file, or plain text otherwise.
+pub enum AnyDocument {
+    Highlighted(HighlightDocument),
+    Plain(FileDocument),
+}
+
+impl AnyDocument {
+    /// Create the highlighted variant if `extension` maps to a known syntax, falling back to the
+    /// plain variant otherwise (including when there is no extension, as when reading from stdin).
+    pub fn new(height: usize, extension: Option<&str>) -> Self {
+        match extension.and_then(|extension| HighlightDocument::new(height, extension)) {
+            Some(document) => AnyDocument::Highlighted(document),
+            None => AnyDocument::Plain(FileDocument::new(height)),
+        }
+    }
+
+    /// Push the given line into the document.
+    ///
+    /// The index of the inserted line is returned.
+    pub fn push_line(&mut self, line: String) -> usize {
+        match self {
+            AnyDocument::Highlighted(document) => document.push_line(line),
+            AnyDocument::Plain(document) => document.push_line(line),
+        }
+    }
+}
+
+impl Index<usize> for AnyDocument {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        match self {
+            AnyDocument::Highlighted(document) => &document[index],
+            AnyDocument::Plain(document) => &document[index],
+        }
+    }
+}
+
+impl Document for AnyDocument {
+    fn max_line_len(&self) -> usize {
+        match self {
+            AnyDocument::Highlighted(document) => document.max_line_len(),
+            AnyDocument::Plain(document) => document.max_line_len(),
+        }
+    }
+    fn len(&self) -> usize {
+        match self {
+            AnyDocument::Highlighted(document) => document.len(),
+            AnyDocument::Plain(document) => document.len(),
+        }
+    }
+    fn styled_line(&self, index: usize) -> Vec<StyledRun> {
+        match self {
+            AnyDocument::Highlighted(document) => document.styled_line(index),
+            AnyDocument::Plain(document) => document.styled_line(index),
+        }
+    }
 }
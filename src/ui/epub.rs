@@ -0,0 +1,461 @@
+// Copyright 2020 Barret Rennie
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+//  option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reading EPUB e-books: unpacking the container, following the OPF spine, and flattening each
+//! chapter's XHTML into styled lines.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Index;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use crossterm::style::{Attribute, ContentStyle};
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+use crate::ui::document::Document;
+use crate::ui::ansi::StyledRun;
+
+/// A single chapter of an EPUB, flattened to styled lines.
+struct Chapter {
+    /// The lines of the chapter.
+    lines: Vec<String>,
+
+    /// The styled runs that make up each line.
+    styled_lines: Vec<Vec<StyledRun>>,
+
+    /// The maximum line length in this chapter.
+    max_line_len: usize,
+
+    /// Links found in this chapter, resolved to the `(line_start, line_end, target_path,
+    /// target_fragment)` they point to.
+    links: Vec<(usize, usize, String, String)>,
+}
+
+/// A document representing an open EPUB e-book.
+pub struct EpubDocument {
+    /// The chapters of the book, in spine order.
+    chapters: Vec<Chapter>,
+
+    /// The index into `chapters` that is currently being displayed.
+    current: usize,
+
+    /// A map from `(chapter-relative path, fragment)` (the normalized form of an `href`, e.g.
+    /// `("text/chapter2.xhtml", "note-3")` for `href="chapter2.xhtml#note-3"`, or `(path, "")` for
+    /// a fragment-less link to the top of a chapter) to the `(chapter, line)` at which it was
+    /// defined, used to resolve internal links.
+    targets: HashMap<(String, String), (usize, usize)>,
+}
+
+impl EpubDocument {
+    /// Open `path` as an EPUB and flatten its spine into chapters.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).context("could not open EPUB for reading")?;
+        let mut archive = ZipArchive::new(file).context("EPUB is not a valid zip archive")?;
+
+        let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+        let opf_path = find_opf_path(&container)?;
+
+        let opf = read_zip_entry(&mut archive, &opf_path)?;
+        let opf_dir = Path::new(&opf_path)
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let spine = parse_spine(&opf)?;
+
+        let mut chapters = Vec::with_capacity(spine.len());
+        let mut targets = HashMap::new();
+
+        for (chapter_index, manifest_path) in spine.iter().enumerate() {
+            let full_path = if opf_dir.is_empty() {
+                manifest_path.clone()
+            } else {
+                format!("{}/{}", opf_dir, manifest_path)
+            };
+
+            let xhtml = read_zip_entry(&mut archive, &full_path)?;
+            let chapter = parse_chapter(&xhtml);
+
+            for (line, id) in chapter.ids.iter() {
+                targets.insert((manifest_path.clone(), id.clone()), (chapter_index, *line));
+            }
+            // A fragment-less link to this chapter (e.g. a table-of-contents entry) targets its
+            // first line.
+            targets.insert((manifest_path.clone(), String::new()), (chapter_index, 0));
+
+            let links = chapter
+                .links
+                .iter()
+                .map(|(start, end, href)| {
+                    let (path, fragment) = resolve_href(manifest_path, href);
+                    (*start, *end, path, fragment)
+                })
+                .collect();
+
+            chapters.push(Chapter {
+                lines: chapter.lines,
+                styled_lines: chapter.styled_lines,
+                max_line_len: chapter.max_line_len,
+                links,
+            });
+        }
+
+        if chapters.is_empty() {
+            return Err(anyhow!("EPUB has no readable chapters"));
+        }
+
+        Ok(EpubDocument {
+            chapters,
+            current: 0,
+            targets,
+        })
+    }
+
+    /// The number of chapters in the book.
+    fn chapter_count(&self) -> usize {
+        self.chapters.len()
+    }
+
+    /// The index of the chapter currently being displayed.
+    fn current_chapter(&self) -> usize {
+        self.current
+    }
+}
+
+impl Index<usize> for EpubDocument {
+    type Output = str;
+
+    fn index(&self, index: usize) -> &str {
+        &self.chapters[self.current].lines[index]
+    }
+}
+
+impl Document for EpubDocument {
+    fn max_line_len(&self) -> usize {
+        self.chapters[self.current].max_line_len
+    }
+
+    fn len(&self) -> usize {
+        self.chapters[self.current].lines.len()
+    }
+
+    fn styled_line(&self, index: usize) -> Vec<StyledRun> {
+        self.chapters[self.current].styled_lines[index].clone()
+    }
+
+    /// Move to the next chapter, if one exists.
+    ///
+    /// Returns `true` if the chapter changed.
+    fn next_chapter(&mut self) -> bool {
+        if self.current + 1 < self.chapters.len() {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Move to the previous chapter, if one exists.
+    ///
+    /// Returns `true` if the chapter changed.
+    fn prev_chapter(&mut self) -> bool {
+        if self.current > 0 {
+            self.current -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Jump directly to the given chapter, clamping to the last chapter if `chapter` is out of
+    /// range.
+    fn goto_chapter(&mut self, chapter: usize) {
+        self.current = chapter.min(self.chapters.len() - 1);
+    }
+
+    /// Resolve the nearest link at or after `line` in the current chapter to the `(chapter,
+    /// line)` it targets.
+    fn follow_link(&self, line: usize) -> Option<(usize, usize)> {
+        let chapter = &self.chapters[self.current];
+        let link = chapter
+            .links
+            .iter()
+            .find(|&&(start, end, _, _)| start <= line && line < end)
+            .or_else(|| chapter.links.iter().find(|&&(start, _, _, _)| start >= line))?;
+
+        self.targets.get(&(link.2.clone(), link.3.clone())).copied()
+    }
+
+    /// Report the current chapter as a `-- chapter N/M` status bar suffix.
+    fn status_suffix(&self) -> Option<String> {
+        Some(format!(
+            "-- chapter {}/{}",
+            self.current_chapter() + 1,
+            self.chapter_count()
+        ))
+    }
+}
+
+/// Read a zip entry's contents as a `String`.
+fn read_zip_entry<R>(archive: &mut ZipArchive<R>, name: &str) -> anyhow::Result<String>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    use std::io::Read;
+
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("EPUB is missing `{}'", name))?;
+    let mut contents = String::new();
+    entry
+        .read_to_string(&mut contents)
+        .with_context(|| format!("`{}' is not valid UTF-8", name))?;
+    Ok(contents)
+}
+
+/// Parse `META-INF/container.xml` to find the path of the OPF package document.
+fn find_opf_path(container_xml: &str) -> anyhow::Result<String> {
+    let mut reader = Reader::from_str(container_xml);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            XmlEvent::Empty(e) | XmlEvent::Start(e) if e.name() == b"rootfile" => {
+                for attr in e.attributes().flatten() {
+                    if attr.key == b"full-path" {
+                        return Ok(attr.unescape_and_decode_value(&reader)?);
+                    }
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Err(anyhow!("container.xml has no rootfile"))
+}
+
+/// Parse the OPF package document's spine into an ordered list of chapter paths, resolved via the
+/// manifest.
+fn parse_spine(opf_xml: &str) -> anyhow::Result<Vec<String>> {
+    let mut reader = Reader::from_str(opf_xml);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+
+    let mut manifest: HashMap<String, String> = HashMap::new();
+    let mut spine_ids = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            XmlEvent::Empty(e) if e.name() == b"item" => {
+                let mut id = None;
+                let mut href = None;
+                for attr in e.attributes().flatten() {
+                    match attr.key {
+                        b"id" => id = Some(attr.unescape_and_decode_value(&reader)?),
+                        b"href" => href = Some(attr.unescape_and_decode_value(&reader)?),
+                        _ => {}
+                    }
+                }
+                if let (Some(id), Some(href)) = (id, href) {
+                    manifest.insert(id, href);
+                }
+            }
+            XmlEvent::Empty(e) if e.name() == b"itemref" => {
+                for attr in e.attributes().flatten() {
+                    if attr.key == b"idref" {
+                        spine_ids.push(attr.unescape_and_decode_value(&reader)?);
+                    }
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(spine_ids
+        .into_iter()
+        .filter_map(|id| manifest.get(&id).cloned())
+        .collect())
+}
+
+/// Resolve a possibly relative, possibly fragment-only `href` found inside `from_chapter_path`'s
+/// XHTML into the `(chapter-relative path, fragment)` pair it targets.
+///
+/// This normalizes same-chapter links (`href="#note"`), cross-chapter links
+/// (`href="chapter3.xhtml#note"`), and fragment-less whole-chapter links (`href="chapter3.xhtml"`)
+/// into the same `(path, fragment)` shape that `targets` is keyed by, resolving relative paths
+/// against the directory of the chapter that contains the link.
+fn resolve_href(from_chapter_path: &str, href: &str) -> (String, String) {
+    let (path_part, fragment) = match href.find('#') {
+        Some(i) => (&href[..i], href[i + 1..].to_string()),
+        None => (href, String::new()),
+    };
+
+    if path_part.is_empty() {
+        return (from_chapter_path.to_string(), fragment);
+    }
+
+    let mut segments: Vec<&str> = match from_chapter_path.rfind('/') {
+        Some(i) => from_chapter_path[..i].split('/').collect(),
+        None => Vec::new(),
+    };
+
+    for segment in path_part.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    (segments.join("/"), fragment)
+}
+
+/// The result of flattening a chapter's XHTML.
+struct FlattenedChapter {
+    lines: Vec<String>,
+    styled_lines: Vec<Vec<StyledRun>>,
+    max_line_len: usize,
+    links: Vec<(usize, usize, String)>,
+    ids: Vec<(usize, String)>,
+}
+
+/// Block-level elements that start a new line.
+fn is_block_element(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"p" | b"div" | b"br" | b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" | b"li"
+    )
+}
+
+/// Flatten a chapter's XHTML into lines, tracking bold/italic spans, anchor links, and ids.
+fn parse_chapter(xhtml: &str) -> FlattenedChapter {
+    let mut reader = Reader::from_str(xhtml);
+    let mut buf = Vec::new();
+
+    let mut lines: Vec<String> = vec![String::new()];
+    let mut styled_lines: Vec<Vec<StyledRun>> = vec![Vec::new()];
+    let mut max_line_len = 0;
+    let mut links = Vec::new();
+    let mut ids = Vec::new();
+
+    let mut style = ContentStyle::default();
+    let mut current_href: Option<(usize, String)> = None;
+
+    macro_rules! push_run {
+        ($text:expr) => {
+            if !$text.is_empty() {
+                let line = lines.last_mut().unwrap();
+                line.push_str($text);
+                max_line_len = std::cmp::max(max_line_len, line.chars().count());
+                styled_lines.last_mut().unwrap().push(StyledRun {
+                    text: $text.to_string(),
+                    style,
+                });
+            }
+        };
+    }
+
+    macro_rules! newline {
+        () => {
+            if !lines.last().unwrap().is_empty() {
+                lines.push(String::new());
+                styled_lines.push(Vec::new());
+            }
+        };
+    }
+
+    loop {
+        let event = match reader.read_event(&mut buf) {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+
+        match event {
+            XmlEvent::Start(e) | XmlEvent::Empty(e) => {
+                let name = e.name().to_vec();
+
+                if is_block_element(&name) {
+                    newline!();
+                }
+
+                match name.as_slice() {
+                    b"b" | b"strong" => style.attributes.set(Attribute::Bold),
+                    b"i" | b"em" => style.attributes.set(Attribute::Italic),
+                    b"a" => {
+                        for attr in e.attributes().flatten() {
+                            if attr.key == b"href" {
+                                let href = attr.unescape_and_decode_value(&reader).unwrap_or_default();
+                                current_href = Some((lines.len() - 1, href));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+
+                for attr in e.attributes().flatten() {
+                    if attr.key == b"id" {
+                        if let Ok(id) = attr.unescape_and_decode_value(&reader) {
+                            ids.push((lines.len() - 1, id));
+                        }
+                    }
+                }
+            }
+            XmlEvent::End(e) => {
+                let name = e.name();
+
+                match name {
+                    b"b" | b"strong" => style.attributes.unset(Attribute::Bold),
+                    b"i" | b"em" => style.attributes.unset(Attribute::Italic),
+                    b"a" => {
+                        if let Some((start, href)) = current_href.take() {
+                            links.push((start, lines.len(), href));
+                        }
+                    }
+                    _ => {}
+                }
+
+                if is_block_element(name) {
+                    newline!();
+                }
+            }
+            XmlEvent::Text(e) => {
+                if let Ok(text) = e.unescape_and_decode(&reader) {
+                    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    push_run!(&text);
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    if lines.last().map_or(false, String::is_empty) && lines.len() > 1 {
+        lines.pop();
+        styled_lines.pop();
+    }
+
+    FlattenedChapter {
+        lines,
+        styled_lines,
+        max_line_len,
+        links,
+        ids,
+    }
+}